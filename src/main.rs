@@ -6,18 +6,25 @@ use codespan_reporting::{
     files::Files,
     term::{
         self,
-        termcolor::{ColorChoice, StandardStream},
+        termcolor::{ColorChoice, StandardStream, WriteColor},
         Config,
     },
 };
-use std::{cmp::Ordering, fmt, ops::Range, sync::Arc};
+use salsa::Durability;
+use std::{cmp::Ordering, collections::HashMap, error::Error, fmt, ops::Range, sync::Arc};
 
 fn main() {
     let mut database = Database::default();
     database.set_file_name(FileId(0), Arc::new("crime.rs".to_owned()));
     database.set_source_text(FileId(0), Arc::new(include_str!("main.rs").to_owned()));
 
-    database.parse(FileId(0));
+    let mut source_root = SourceRoot::default();
+    source_root.insert(FileId(0), VfsPath::new("/crime.rs"));
+    database.set_source_root(Arc::new(source_root));
+
+    let writer = StandardStream::stderr(ColorChoice::Auto);
+    let config = Config::default();
+    emit_diagnostics(&database, &mut writer.lock(), &config, FileId(0)).unwrap();
 }
 
 // A standard salsa database to hold all our query info
@@ -29,6 +36,37 @@ pub struct Database {
 
 impl salsa::Database for Database {}
 
+impl Database {
+    /// Set a file's name, marking the input with `durability`.
+    ///
+    /// See [`Database::set_source_text_with_durability`] for when to reach for a
+    /// non-default durability
+    pub fn set_file_name_with_durability(
+        &mut self,
+        file: FileId,
+        name: Arc<String>,
+        durability: Durability,
+    ) {
+        SourceDatabase::set_file_name_with_durability(self, file, name, durability);
+    }
+
+    /// Set a file's source text, marking the input with `durability`.
+    ///
+    /// Immutable/library files that never change should be set to [`Durability::HIGH`].
+    /// A revision that only bumps [`Durability::LOW`] inputs then lets salsa skip
+    /// revalidating the derived queries (`line_starts`, `line_index`, ...) built from the
+    /// untouched high-durability files entirely, which matters once the database holds
+    /// many large files
+    pub fn set_source_text_with_durability(
+        &mut self,
+        file: FileId,
+        text: Arc<String>,
+        durability: Durability,
+    ) {
+        SourceDatabase::set_source_text_with_durability(self, file, text, durability);
+    }
+}
+
 // Implement upcasting for the main database into every query group it holds
 impl Upcast<dyn SourceDatabase> for Database {
     fn upcast(&self) -> &dyn SourceDatabase {
@@ -47,28 +85,100 @@ pub trait Upcast<T: ?Sized> {
     fn upcast(&self) -> &T;
 }
 
+/// The error returned when a query is abandoned because a newer edit cancelled its revision
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the current revision was cancelled by a pending write")
+    }
+}
+
+impl Error for Cancelled {}
+
+// Lets a query notice when a write is pending on another thread
+pub trait CheckCanceled {
+    /// Whether a pending write has cancelled the current revision.
+    ///
+    /// Long-running derived queries should poll this and bail out early so an interactive
+    /// edit can interrupt them instead of leaving the caller waiting on stale results
+    fn check_canceled(&self) -> bool;
+}
+
+impl<DB> CheckCanceled for DB
+where
+    DB: salsa::Database + ?Sized,
+{
+    fn check_canceled(&self) -> bool {
+        self.salsa_runtime().is_current_revision_canceled()
+    }
+}
+
 // If we want to be able to use `&dyn ParseDatabase` for rendering errors we must have `Upcast<dyn SourceDatabase>` as a supertrait
 #[salsa::query_group(ParseDatabaseStorage)]
 pub trait ParseDatabase: salsa::Database + SourceDatabase + Upcast<dyn SourceDatabase> {
-    // Salsa currently doesn't allow returning unit in a non-explicit way, see https://github.com/salsa-rs/salsa/issues/149
-    fn parse(&self, file: FileId) -> ();
+    /// The diagnostics produced for a file, computed once and then cached.
+    ///
+    /// Because this is a derived query, editing an unrelated file leaves a file's
+    /// diagnostics cached, and callers can collect or serialize the returned vector
+    /// instead of being forced to render colored text to a terminal
+    ///
+    /// `no_eq` because `codespan_reporting::Diagnostic` implements neither `PartialEq` nor
+    /// `Eq`, so salsa can't use value equality to backdate this query; recomputation simply
+    /// replaces the cached vector
+    #[salsa::no_eq]
+    fn diagnostics(&self, file: FileId) -> Arc<Vec<Diagnostic<FileId>>>;
 }
 
-// Right now all this does is emit an error, but that's just an example
-fn parse(db: &dyn ParseDatabase, file: FileId) {
-    let writer = StandardStream::stderr(ColorChoice::Auto);
-    let config = Config::default();
-
-    let diag = Diagnostic::error()
+// Right now all this does is report a single error, but that's just an example
+fn diagnostics(db: &dyn ParseDatabase, file: FileId) -> Arc<Vec<Diagnostic<FileId>>> {
+    let diagnostic = Diagnostic::error()
         .with_message("This is a crime")
         .with_labels(vec![Label::new(
             LabelStyle::Primary,
-            FileId(0),
+            file,
             db.line_range(file, 14).unwrap().start..db.line_range(file, 20).unwrap().end - 1,
         )]);
 
-    // Using `FileCache::upcast` we can take anything that implements `Upcast<dyn SourceDatabase` and use it for emitting errors
-    term::emit(&mut writer.lock(), &config, &FileCache::upcast(db), &diag).unwrap();
+    Arc::new(vec![diagnostic])
+}
+
+/// Render the cached diagnostics for a file to `writer`.
+///
+/// Using `FileCache::upcast` we can take anything that implements `Upcast<dyn SourceDatabase>`
+/// and use it for emitting errors, so rendering stays decoupled from the analysis that
+/// produced the diagnostics in the first place
+pub fn emit_diagnostics(
+    db: &dyn ParseDatabase,
+    writer: &mut dyn WriteColor,
+    config: &Config,
+    file: FileId,
+) -> Result<(), std::io::Error> {
+    let cache = FileCache::upcast(db);
+
+    for diagnostic in db.diagnostics(file).iter() {
+        term::emit(writer, config, &cache, diagnostic)?;
+    }
+
+    Ok(())
+}
+
+/// Compute a file's diagnostics as a cancellable entry point.
+///
+/// If a newer edit has already cancelled the current revision this returns `Err(Cancelled)`
+/// rather than handing back a result salsa is about to invalidate; callers should retry
+/// against the new revision. salsa 0.15's cancellation primitive is a revision check, so
+/// this is polled at the entry point rather than unwinding mid-query
+pub fn cancellable_diagnostics(
+    db: &dyn ParseDatabase,
+    file: FileId,
+) -> Result<Arc<Vec<Diagnostic<FileId>>>, Cancelled> {
+    if db.check_canceled() {
+        return Err(Cancelled);
+    }
+
+    Ok(db.diagnostics(file))
 }
 
 /// The database that holds all source files
@@ -82,6 +192,19 @@ pub trait SourceDatabase: salsa::Database {
     #[salsa::input]
     fn source_text(&self, file: FileId) -> Arc<String>;
 
+    /// The set of paths known to the database, mapped to their [`FileId`]s
+    #[salsa::input]
+    fn source_root(&self) -> Arc<SourceRoot>;
+
+    /// The [`FileId`] a path belongs to, if it has been loaded into the [`SourceRoot`]
+    fn file_id(&self, path: VfsPath) -> Option<FileId>;
+
+    /// The path a [`FileId`] was loaded from
+    fn file_path(&self, file: FileId) -> Option<VfsPath>;
+
+    /// Resolve a path relative to `anchor`'s directory into a [`FileId`]
+    fn resolve_path(&self, anchor: FileId, relative: String) -> Option<FileId>;
+
     /// The length of a source file
     fn source_length(&self, file: FileId) -> usize;
 
@@ -91,8 +214,17 @@ pub trait SourceDatabase: salsa::Database {
     /// The index a line starts at
     fn line_start(&self, file: FileId, line_index: usize) -> Option<usize>;
 
-    /// The line which a byte index falls on
-    fn line_index(&self, file: FileId, byte_index: usize) -> Option<usize>;
+    /// A line index mapping byte offsets to line/column positions for the file
+    fn line_index(&self, file: FileId) -> Arc<LineIndex>;
+
+    /// The `(line, column)` a byte index falls on, where `col` is a UTF-8 byte column
+    fn line_col(&self, file: FileId, byte_index: usize) -> Option<LineCol>;
+
+    /// The `(line, column)` a byte index falls on, where `col` counts UTF-16 code units
+    fn line_col_utf16(&self, file: FileId, byte_index: usize) -> Option<LineColUtf16>;
+
+    /// The byte offset of a `(line, column)` position
+    fn offset(&self, file: FileId, position: LineCol) -> Option<usize>;
 
     /// The range of a single line
     fn line_range(&self, file: FileId, line_index: usize) -> Option<Range<usize>>;
@@ -102,6 +234,20 @@ fn source_length(db: &dyn SourceDatabase, file: FileId) -> usize {
     db.source_text(file).len()
 }
 
+fn file_id(db: &dyn SourceDatabase, path: VfsPath) -> Option<FileId> {
+    db.source_root().file_id(&path)
+}
+
+fn file_path(db: &dyn SourceDatabase, file: FileId) -> Option<VfsPath> {
+    db.source_root().path(file)
+}
+
+fn resolve_path(db: &dyn SourceDatabase, anchor: FileId, relative: String) -> Option<FileId> {
+    let directory = db.file_path(anchor)?.parent()?;
+
+    db.file_id(directory.join(&relative))
+}
+
 fn line_starts(db: &dyn SourceDatabase, file: FileId) -> Arc<Vec<usize>> {
     Arc::new(
         core::iter::once(0)
@@ -120,11 +266,54 @@ fn line_start(db: &dyn SourceDatabase, file: FileId, line_index: usize) -> Optio
     }
 }
 
-fn line_index(db: &dyn SourceDatabase, file: FileId, byte_index: usize) -> Option<usize> {
-    match db.line_starts(file).binary_search(&byte_index) {
-        Ok(line) => Some(line),
-        Err(next_line) => Some(next_line - 1),
+fn line_index(db: &dyn SourceDatabase, file: FileId) -> Arc<LineIndex> {
+    let line_starts = db.line_starts(file);
+    let source = db.source_text(file);
+
+    // Record every non-ASCII char by the line it lives on so UTF-16 columns can be
+    // recovered later without re-scanning the whole file on each lookup
+    let mut wide_chars: HashMap<u32, Vec<WideChar>> = HashMap::new();
+    let mut line = 0usize;
+    for (byte, ch) in source.char_indices() {
+        // Scanning a huge file is the expensive part; bail promptly if a newer edit has
+        // already cancelled this revision, since salsa will discard the result anyway
+        if db.check_canceled() {
+            break;
+        }
+
+        while line + 1 < line_starts.len() && line_starts[line + 1] <= byte {
+            line += 1;
+        }
+
+        if !ch.is_ascii() {
+            wide_chars.entry(line as u32).or_default().push(WideChar {
+                col: byte - line_starts[line],
+                len_utf8: ch.len_utf8(),
+                len_utf16: ch.len_utf16(),
+            });
+        }
     }
+
+    Arc::new(LineIndex {
+        line_starts,
+        wide_chars,
+    })
+}
+
+fn line_col(db: &dyn SourceDatabase, file: FileId, byte_index: usize) -> Option<LineCol> {
+    db.line_index(file).line_col(byte_index)
+}
+
+fn line_col_utf16(
+    db: &dyn SourceDatabase,
+    file: FileId,
+    byte_index: usize,
+) -> Option<LineColUtf16> {
+    db.line_index(file).line_col_utf16(byte_index)
+}
+
+fn offset(db: &dyn SourceDatabase, file: FileId, position: LineCol) -> Option<usize> {
+    db.line_index(file).offset(position)
 }
 
 fn line_range(db: &dyn SourceDatabase, file: FileId, line_index: usize) -> Option<Range<usize>> {
@@ -172,7 +361,9 @@ impl<'a> Files<'a> for FileCache<'a> {
     }
 
     fn line_index(&self, file: FileId, byte_index: usize) -> Option<usize> {
-        self.source.line_index(file, byte_index)
+        self.source
+            .line_col(file, byte_index)
+            .map(|position| position.line as usize)
     }
 
     fn line_range(&self, file: FileId, line_index: usize) -> Option<Range<usize>> {
@@ -189,3 +380,310 @@ impl fmt::Debug for FileCache<'_> {
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct FileId(pub u32);
+
+/// A precomputed mapping from byte offsets to line/column positions for a single file.
+///
+/// Holds the file's line-start vector plus, for every line that contains non-ASCII
+/// characters, the wide chars on that line. Byte→line lookups are a binary search over
+/// the line starts; UTF-16 columns are recovered by walking that line's wide chars.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineIndex {
+    /// The byte index every line starts at, as produced by [`SourceDatabase::line_starts`]
+    line_starts: Arc<Vec<usize>>,
+    /// The non-ASCII chars on each line, keyed by line number
+    wide_chars: HashMap<u32, Vec<WideChar>>,
+}
+
+impl LineIndex {
+    /// The `(line, col)` the byte index falls on, where `col` is a UTF-8 byte column
+    pub fn line_col(&self, byte_index: usize) -> Option<LineCol> {
+        let line = self
+            .line_starts
+            .partition_point(|&start| start <= byte_index)
+            .checked_sub(1)?;
+
+        Some(LineCol {
+            line: line as u32,
+            col: (byte_index - self.line_starts[line]) as u32,
+        })
+    }
+
+    /// The `(line, col)` the byte index falls on, where `col` counts UTF-16 code units
+    pub fn line_col_utf16(&self, byte_index: usize) -> Option<LineColUtf16> {
+        let LineCol { line, col } = self.line_col(byte_index)?;
+
+        // Every wide char before `col` on this line cost more UTF-8 bytes than UTF-16
+        // code units, so walk them back out of the column
+        let mut utf16_col = col;
+        if let Some(wide_chars) = self.wide_chars.get(&line) {
+            for wide in wide_chars {
+                if (wide.col as u32) < col {
+                    utf16_col -= (wide.len_utf8 - wide.len_utf16) as u32;
+                }
+            }
+        }
+
+        Some(LineColUtf16 {
+            line,
+            col: utf16_col,
+        })
+    }
+
+    /// The byte offset of a `(line, col)` position, the inverse of [`LineIndex::line_col`]
+    pub fn offset(&self, position: LineCol) -> Option<usize> {
+        let line_start = self.line_starts.get(position.line as usize)?;
+
+        Some(line_start + position.col as usize)
+    }
+}
+
+/// A position within a file as a zero-based line and UTF-8 byte column
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LineCol {
+    /// The zero-based line number
+    pub line: u32,
+    /// The UTF-8 byte offset from the start of the line
+    pub col: u32,
+}
+
+/// A position within a file as a zero-based line and UTF-16 code unit column, as used by LSP
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LineColUtf16 {
+    /// The zero-based line number
+    pub line: u32,
+    /// The UTF-16 code unit offset from the start of the line
+    pub col: u32,
+}
+
+/// A virtual, `/`-separated path to a source file.
+///
+/// Paths are virtual so the database can be populated from a fixture or an in-memory
+/// project without touching the real filesystem
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VfsPath(Arc<String>);
+
+impl VfsPath {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self(Arc::new(path.into()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The directory containing this path, if any
+    pub fn parent(&self) -> Option<VfsPath> {
+        let slash = self.0.rfind('/')?;
+
+        Some(VfsPath(Arc::new(self.0[..slash].to_owned())))
+    }
+
+    /// Resolve `relative` against this directory, collapsing `.` and `..` segments.
+    ///
+    /// A `relative` that starts with `/` is treated as absolute and replaces this path
+    pub fn join(&self, relative: &str) -> VfsPath {
+        let mut segments: Vec<&str> = if relative.starts_with('/') {
+            Vec::new()
+        } else {
+            self.0.split('/').filter(|segment| !segment.is_empty()).collect()
+        };
+
+        for segment in relative.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => {
+                    segments.pop();
+                }
+                segment => segments.push(segment),
+            }
+        }
+
+        VfsPath(Arc::new(format!("/{}", segments.join("/"))))
+    }
+}
+
+/// The set of paths loaded into a [`SourceDatabase`], mapping each path to a [`FileId`].
+///
+/// This is the input that lets a multi-file project be loaded at once and lets
+/// `include`/`mod`-style relative paths be resolved into concrete files
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceRoot {
+    path_to_file: HashMap<VfsPath, FileId>,
+    file_to_path: HashMap<FileId, VfsPath>,
+}
+
+impl SourceRoot {
+    /// Register `file` as living at `path`
+    pub fn insert(&mut self, file: FileId, path: VfsPath) {
+        self.path_to_file.insert(path.clone(), file);
+        self.file_to_path.insert(file, path);
+    }
+
+    /// The [`FileId`] registered for `path`
+    pub fn file_id(&self, path: &VfsPath) -> Option<FileId> {
+        self.path_to_file.get(path).copied()
+    }
+
+    /// The path `file` was registered at
+    pub fn path(&self, file: FileId) -> Option<VfsPath> {
+        self.file_to_path.get(&file).cloned()
+    }
+}
+
+/// Build a [`Database`] from a single annotated fixture string.
+///
+/// A fixture is split into embedded files by `//- /path/to/file.rs` header lines: the text
+/// from one header up to the next is that file's contents, and files are assigned sequential
+/// [`FileId`]s starting at `0`. Two markers are recognised inside a file's body and stripped
+/// before the text is stored: a single `$0` records a cursor offset, and a pair of `$0`s
+/// delimits a byte range. Both come back as [`FileId`] + offset/[`Range`] so they can be fed
+/// straight into `line_range` or a [`Diagnostic`] label.
+pub mod fixture {
+    use super::*;
+
+    /// A database populated from a fixture string, along with the positions it marked
+    pub struct Fixture {
+        /// The populated database, with every embedded file's name, text and path set
+        pub database: Database,
+        /// The `$0` cursor position, if the fixture contained a lone marker
+        pub cursor: Option<FilePosition>,
+        /// The ranges delimited by paired `$0` markers, in file order
+        pub ranges: Vec<FileRange>,
+    }
+
+    /// A cursor position within a fixture file
+    pub struct FilePosition {
+        pub file: FileId,
+        pub offset: usize,
+    }
+
+    /// A byte range within a fixture file
+    pub struct FileRange {
+        pub file: FileId,
+        pub range: Range<usize>,
+    }
+
+    /// Parse `fixture` into a fully populated [`Database`] and the positions it marked
+    pub fn parse(fixture: &str) -> Fixture {
+        let mut database = Database::default();
+        let mut source_root = SourceRoot::default();
+        let mut cursor = None;
+        let mut ranges = Vec::new();
+
+        for (id, (path, body)) in split_files(fixture).into_iter().enumerate() {
+            let file = FileId(id as u32);
+            let (text, markers) = strip_markers(&body);
+
+            match markers.as_slice() {
+                [] => {}
+                [offset] => cursor = Some(FilePosition { file, offset: *offset }),
+                [start, end, ..] => ranges.push(FileRange {
+                    file,
+                    range: *start..*end,
+                }),
+            }
+
+            let path = VfsPath::new(path);
+            database.set_file_name(file, Arc::new(path.as_str().to_owned()));
+            database.set_source_text(file, Arc::new(text));
+            source_root.insert(file, path);
+        }
+
+        database.set_source_root(Arc::new(source_root));
+
+        Fixture {
+            database,
+            cursor,
+            ranges,
+        }
+    }
+
+    /// Split a fixture into `(path, body)` pairs on `//-` header lines
+    fn split_files(fixture: &str) -> Vec<(String, String)> {
+        let mut files = Vec::new();
+        let mut path: Option<String> = None;
+        let mut body = String::new();
+
+        for line in fixture.lines() {
+            if let Some(header) = line.trim_start().strip_prefix("//-") {
+                if let Some(path) = path.take() {
+                    files.push((path, std::mem::take(&mut body)));
+                } else {
+                    // Anything before the first header is preamble and is discarded
+                    body.clear();
+                }
+
+                path = Some(header.trim().to_owned());
+            } else {
+                body.push_str(line);
+                body.push('\n');
+            }
+        }
+
+        match path {
+            Some(path) => files.push((path, body)),
+            // A fixture with no headers at all is treated as a single anonymous file
+            None if !body.trim().is_empty() => files.push(("/fixture.rs".to_owned(), body)),
+            None => {}
+        }
+
+        files
+    }
+
+    /// Strip every `$0` marker from `body`, returning the cleaned text and the byte offsets
+    /// the markers sat at in that text
+    fn strip_markers(body: &str) -> (String, Vec<usize>) {
+        let mut text = String::new();
+        let mut markers = Vec::new();
+        let mut rest = body;
+
+        while let Some(index) = rest.find("$0") {
+            text.push_str(&rest[..index]);
+            markers.push(text.len());
+            rest = &rest[index + "$0".len()..];
+        }
+        text.push_str(rest);
+
+        (text, markers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixture_positions_and_utf16_columns() {
+        let fx = fixture::parse("//- /lib.rs\nconst π: u32 = $0;\n//- /main.rs\nfn main() {}\n");
+
+        // The lone `$0` is reported as a cursor in the first embedded file
+        let cursor = fx.cursor.expect("fixture has a cursor marker");
+        assert_eq!(cursor.file, FileId(0));
+        assert_eq!(cursor.offset, 16);
+
+        // The second `//-` header became its own sequentially numbered file
+        assert_eq!(fx.database.file_id(VfsPath::new("/main.rs")), Some(FileId(1)));
+
+        // The multi-byte `π` leaves the UTF-8 byte column unchanged but shifts the UTF-16
+        // column back by the one code unit it saves
+        assert_eq!(
+            fx.database.line_col(cursor.file, cursor.offset),
+            Some(LineCol { line: 0, col: 16 }),
+        );
+        assert_eq!(
+            fx.database.line_col_utf16(cursor.file, cursor.offset),
+            Some(LineColUtf16 { line: 0, col: 15 }),
+        );
+    }
+}
+
+/// A non-ASCII char recorded against the line it appears on
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct WideChar {
+    /// The UTF-8 byte column the char starts at within its line
+    col: usize,
+    /// The char's length in UTF-8 bytes
+    len_utf8: usize,
+    /// The char's length in UTF-16 code units
+    len_utf16: usize,
+}